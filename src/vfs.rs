@@ -1,8 +1,17 @@
 use std::collections::HashMap;
+use std::error;
+use std::fmt;
 use std::fs::{self, File};
-use std::io::Read;
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
-use std::time::Instant;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
 
 use core::Config;
 use plugin::PluginChain;
@@ -22,67 +31,383 @@ pub struct Vfs {
 
     /// A chronologically-sorted list of routes that changed since the Vfs was
     /// created, along with a timestamp denoting when.
-    pub change_history: Vec<VfsChange>,
+    ///
+    /// Wrapped in a mutex so that `VfsWatcher`'s background thread can append
+    /// to it concurrently with callers of `add_change`.
+    pub change_history: Arc<Mutex<Vec<VfsChange>>>,
+
+    /// Canonical storage for every route the Vfs has seen, so that
+    /// `VfsItem`s and `VfsChange`s can reference routes with a cheap-to-copy
+    /// `FileId` instead of cloning a `Vec<String>` on every read.
+    file_interner: Arc<Mutex<PathInterner>>,
+
+    /// Compiled ignore patterns for each partition, keyed by partition name.
+    ignores: HashMap<String, IgnoreMatcher>,
+
+    /// Where the leaf IO for `read`, `write`, and `delete` actually happens.
+    /// Swappable so tests can mount a `MemoryBackend` instead of touching disk.
+    backend: Box<dyn VfsBackend>,
 
     plugin_chain: &'static PluginChain,
 
     config: Config,
+
+    /// The background filesystem watcher, if this `Vfs` has been asked to
+    /// start one via `start_watching`.
+    watcher: Option<VfsWatcher>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct VfsChange {
     timestamp: f64,
-    route: Vec<String>,
+    id: FileId,
+}
+
+impl VfsChange {
+    pub fn id(&self) -> FileId {
+        self.id
+    }
+}
+
+/// An interned route, cheap to copy and store in place of the `Vec<String>`
+/// it stands for. Resolve it back to a route with `Vfs::route`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct FileId(u32);
+
+/// A single interned path component: its name and a pointer back to its
+/// parent, if any. Routes are reconstructed by walking parents to the root.
+#[derive(Debug)]
+struct InternedNode {
+    parent: Option<FileId>,
+    name: String,
+}
+
+/// Interns routes into small integer `FileId`s, storing each distinct route
+/// as a trie of path components rather than a full `Vec<String>` per node,
+/// so that interning a child only ever clones its own name, not every
+/// ancestor's name as well.
+#[derive(Debug, Default)]
+struct PathInterner {
+    nodes: Vec<InternedNode>,
+    children: HashMap<(Option<FileId>, String), FileId>,
+}
+
+impl PathInterner {
+    fn new() -> PathInterner {
+        PathInterner {
+            nodes: Vec::new(),
+            children: HashMap::new(),
+        }
+    }
+
+    /// Interns `name` as a child of `parent` (or as a root, if `parent` is
+    /// `None`), returning the same `FileId` for repeated calls with the same
+    /// `(parent, name)` pair.
+    fn intern_child(&mut self, parent: Option<FileId>, name: &str) -> FileId {
+        let key = (parent, name.to_string());
+
+        if let Some(&id) = self.children.get(&key) {
+            return id;
+        }
+
+        let id = FileId(self.nodes.len() as u32);
+        self.nodes.push(InternedNode { parent, name: key.1.clone() });
+        self.children.insert(key, id);
+
+        id
+    }
+
+    /// Interns a full route by walking it component-by-component through
+    /// `intern_child`, reusing whatever prefix of it is already known.
+    fn intern(&mut self, route: &[String]) -> FileId {
+        let mut parent = None;
+
+        for name in route {
+            parent = Some(self.intern_child(parent, name));
+        }
+
+        parent.expect("route passed to PathInterner::intern must not be empty")
+    }
+
+    /// Resolves a `FileId` back to the route it was interned from by
+    /// walking its parent chain up to the root.
+    fn route(&self, id: FileId) -> Vec<String> {
+        let mut parts = Vec::new();
+        let mut current = Some(id);
+
+        while let Some(id) = current {
+            let node = &self.nodes[id.0 as usize];
+            parts.push(node.name.clone());
+            current = node.parent;
+        }
+
+        parts.reverse();
+        parts
+    }
+}
+
+/// Compiled ignore patterns for a single partition: anything matching
+/// `ignore` is skipped, unless it also matches `allow`, which re-includes it.
+#[derive(Debug, Clone)]
+struct IgnoreMatcher {
+    ignore: GlobSet,
+    allow: GlobSet,
+}
+
+impl IgnoreMatcher {
+    fn compile(patterns: &[String]) -> IgnoreMatcher {
+        let mut ignore_builder = GlobSetBuilder::new();
+        let mut allow_builder = GlobSetBuilder::new();
+
+        for pattern in patterns {
+            match pattern.strip_prefix('!') {
+                Some(negated) => {
+                    if let Ok(glob) = Glob::new(&anchor_pattern(negated)) {
+                        allow_builder.add(glob);
+                    }
+                },
+                None => {
+                    if let Ok(glob) = Glob::new(&anchor_pattern(pattern)) {
+                        ignore_builder.add(glob);
+                    }
+                },
+            }
+        }
+
+        IgnoreMatcher {
+            ignore: ignore_builder.build().unwrap_or_else(|_| GlobSetBuilder::new().build().unwrap()),
+            allow: allow_builder.build().unwrap_or_else(|_| GlobSetBuilder::new().build().unwrap()),
+        }
+    }
+
+    fn is_ignored(&self, route: &[String]) -> bool {
+        let joined = route.join("/");
+
+        self.ignore.is_match(&joined) && !self.allow.is_match(&joined)
+    }
+}
+
+/// Applies gitignore-style anchoring to a single (already de-negated) glob
+/// pattern: a pattern with no leading `**/` or `/` is implicitly prefixed
+/// with `**/` so it matches at any depth, the way `node_modules/` or `.git/`
+/// are expected to, instead of only matching a route that happens to equal
+/// the pattern exactly. A leading `/` anchors the pattern to the partition
+/// root, matching git's own convention; a trailing `/` is stripped since
+/// `route.join("/")` never produces a trailing separator to match against.
+fn anchor_pattern(pattern: &str) -> String {
+    let pattern = pattern.trim_end_matches('/');
+
+    if pattern.starts_with("**/") {
+        pattern.to_string()
+    } else if let Some(rooted) = pattern.strip_prefix('/') {
+        rooted.to_string()
+    } else {
+        format!("**/{}", pattern)
+    }
+}
+
+/// Checks `route` against the ignore matcher for its partition, if any.
+/// Patterns are matched against the route *relative to the partition root*
+/// (i.e. with the leading partition name stripped), so a `/`-rooted pattern
+/// like `/build` anchors to the partition root rather than to a literal
+/// top-level route component named after the partition itself.
+fn route_is_ignored(ignores: &HashMap<String, IgnoreMatcher>, route: &[String]) -> bool {
+    let (partition_name, rest) = match route.split_first() {
+        Some(split) => split,
+        None => return false,
+    };
+
+    ignores.get(partition_name)
+        .map(|matcher| matcher.is_ignored(rest))
+        .unwrap_or(false)
+}
+
+/// Collects the route of every file that a write of `item` to `route` would
+/// actually put on disk: `route` itself for a `File`, or `route` joined with
+/// each descendant's name for a `Dir`. An empty directory has no files to
+/// name, so it falls back to reporting `route` itself, the same way a write
+/// of a single file would.
+fn leaf_file_routes(route: &[String], item: &VfsItem) -> Vec<Vec<String>> {
+    match *item {
+        VfsItem::File { .. } => vec![route.to_vec()],
+        VfsItem::Dir { ref children, .. } => {
+            if children.is_empty() {
+                return vec![route.to_vec()];
+            }
+
+            let mut routes = Vec::new();
+
+            for (name, child) in children {
+                let mut child_route = route.to_vec();
+                child_route.push(name.clone());
+
+                routes.extend(leaf_file_routes(&child_route, child));
+            }
+
+            routes
+        },
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase", tag = "type")]
 pub enum VfsItem {
     File {
-        route: Vec<String>,
+        id: FileId,
+        name: String,
         contents: String,
     },
     Dir {
-        route: Vec<String>,
+        id: FileId,
+        name: String,
         children: HashMap<String, VfsItem>,
     },
 }
 
 impl VfsItem {
-    pub fn name(&self) -> &String {
-        self.route().last().unwrap()
+    pub fn id(&self) -> FileId {
+        match *self {
+            VfsItem::File { id, .. } => id,
+            VfsItem::Dir { id, .. } => id,
+        }
     }
 
-    pub fn route(&self) -> &[String] {
-        match self {
-            &VfsItem::File { ref route, .. } => route,
-            &VfsItem::Dir { ref route, .. } => route,
+    /// Resolves this item's full route, given the `Vfs` it was read from.
+    /// `VfsItem` only carries the interned `FileId`, not the route itself,
+    /// so resolving it back to a `Vec<String>` needs the interner that
+    /// produced the id; equivalent to `vfs.route(item.id())`.
+    pub fn route(&self, vfs: &Vfs) -> Vec<String> {
+        vfs.route(self.id())
+    }
+
+    pub fn name(&self) -> &str {
+        match *self {
+            VfsItem::File { ref name, .. } => name,
+            VfsItem::Dir { ref name, .. } => name,
         }
     }
 }
 
 impl Vfs {
     pub fn new(config: Config, plugin_chain: &'static PluginChain) -> Vfs {
+        Vfs::with_backend(config, plugin_chain, Box::new(RealFsBackend))
+    }
+
+    /// Like `new`, but with an explicit `VfsBackend` instead of the default
+    /// `RealFsBackend`. Lets tests mount a `MemoryBackend` and exercise
+    /// change tracking and the plugin chain with no filesystem dependency.
+    pub fn with_backend(config: Config, plugin_chain: &'static PluginChain, backend: Box<dyn VfsBackend>) -> Vfs {
         Vfs {
             partitions: HashMap::new(),
             start_time: Instant::now(),
-            change_history: Vec::new(),
+            change_history: Arc::new(Mutex::new(Vec::new())),
+            file_interner: Arc::new(Mutex::new(PathInterner::new())),
+            ignores: HashMap::new(),
+            backend,
             plugin_chain,
             config,
+            watcher: None,
         }
     }
 
-    fn route_to_path(&self, route: &[String]) -> Option<PathBuf> {
-        let (partition_name, rest) = match route.split_first() {
-            Some((first, rest)) => (first, rest),
-            None => return None,
-        };
+    /// Interns `route`, returning the `FileId` that refers to it. Interning
+    /// the same route twice returns the same id.
+    pub fn file_id(&self, route: &[String]) -> FileId {
+        self.file_interner.lock().unwrap().intern(route)
+    }
 
-        let partition = match self.partitions.get(partition_name) {
-            Some(v) => v,
-            None => return None,
-        };
+    /// Interns `name` as a child of `parent`, returning the `FileId` that
+    /// refers to it. Cheaper than `file_id` when the caller already knows
+    /// the parent's id, since it only touches `name` instead of rebuilding
+    /// and cloning the whole route.
+    fn file_id_child(&self, parent: FileId, name: &str) -> FileId {
+        self.file_interner.lock().unwrap().intern_child(Some(parent), name)
+    }
+
+    /// Resolves a `FileId` back to the route it was interned from. Returns
+    /// an owned `Vec<String>` rather than `&[String]`: the interner lives
+    /// behind a `Mutex` so other threads (the background watcher) can intern
+    /// routes concurrently, and a borrow can't outlive the lock guard.
+    pub fn route(&self, id: FileId) -> Vec<String> {
+        self.file_interner.lock().unwrap().route(id)
+    }
+
+    /// Mounts `path` as partition `name`, compiling `ignore_patterns` into a
+    /// matcher that `read` (and the watcher, once started) use to skip
+    /// matching entries under this partition.
+    ///
+    /// Patterns are glob syntax evaluated against the route *relative to this
+    /// partition* (i.e. with the partition name itself excluded) joined with
+    /// `/`. A pattern with no leading `**/` or `/` is implicitly anchored
+    /// with `**/`, gitignore-style, so plain patterns like `node_modules/` or
+    /// `.git/` match at any depth rather than only at the partition root;
+    /// write `**/*.tmp` explicitly when matching a file extension everywhere,
+    /// or `/build` to anchor a pattern to the partition root. A pattern
+    /// prefixed with `!` re-includes routes that any other pattern excluded,
+    /// whatever order the patterns are listed in; it does not re-include a
+    /// route whose parent directory is itself ignored.
+    pub fn mount_partition<S: Into<String>>(&mut self, name: S, path: PathBuf, ignore_patterns: &[String]) {
+        let name = name.into();
+
+        // Only insert a matcher when there's actually something to match, so
+        // `self.ignores.is_empty()` stays a meaningful "no partition has any
+        // ignore patterns configured" check instead of going permanently
+        // false on the first `mount_partition` call regardless of patterns.
+        if !ignore_patterns.is_empty() {
+            self.ignores.insert(name.clone(), IgnoreMatcher::compile(ignore_patterns));
+        }
+
+        self.partitions.insert(name, path);
+    }
+
+    /// Checks whether `id` is ignored under its partition's ignore patterns.
+    /// Resolving `id` back to a route costs one walk up the interner's
+    /// parent chain, so this skips straight to `false` when no partition has
+    /// any ignore patterns configured, which is the common case during a
+    /// directory walk.
+    fn is_ignored(&self, id: FileId) -> bool {
+        if self.ignores.is_empty() {
+            return false;
+        }
+
+        route_is_ignored(&self.ignores, &self.route(id))
+    }
+
+    /// Starts a background thread that watches every partition currently
+    /// registered for filesystem changes and feeds them into `change_history`
+    /// via the plugin chain, the same way `add_change` does. Partitions added
+    /// after this call are not picked up; call `stop_watching` and
+    /// `start_watching` again to pick up the new set.
+    ///
+    /// Does nothing if a watcher is already running.
+    pub fn start_watching(&mut self) {
+        if self.watcher.is_some() {
+            return;
+        }
+
+        self.watcher = Some(VfsWatcher::start(
+            self.partitions.clone(),
+            self.ignores.clone(),
+            self.plugin_chain,
+            self.start_time,
+            Arc::clone(&self.change_history),
+            Arc::clone(&self.file_interner),
+        ));
+    }
+
+    /// Stops the background filesystem watcher, if one is running.
+    pub fn stop_watching(&mut self) {
+        self.watcher = None;
+    }
+
+    fn route_to_path(&self, route: &[String]) -> Result<PathBuf, VfsError> {
+        let (partition_name, rest) = route.split_first()
+            .ok_or_else(|| VfsError::NoSuchPartition(String::new()))?;
+
+        let partition = self.partitions.get(partition_name)
+            .ok_or_else(|| VfsError::NoSuchPartition(partition_name.clone()))?;
 
         // It's possible that the partition points to a file if `rest` is empty.
         // Joining "" onto a path will put a trailing slash on, which causes
@@ -96,78 +421,46 @@ impl Vfs {
             partition.join(relative)
         };
 
-        Some(full_path)
-    }
-
-    fn read_dir<P: AsRef<Path>>(&self, route: &[String], path: P) -> Result<VfsItem, ()> {
-        let path = path.as_ref();
-        let reader = match fs::read_dir(path) {
-            Ok(v) => v,
-            Err(_) => return Err(()),
-        };
-
-        let mut children = HashMap::new();
-
-        for entry in reader {
-            let entry = match entry {
-                Ok(v) => v,
-                Err(_) => return Err(()),
-            };
-
-            let path = entry.path();
-            let name = path.file_name().unwrap().to_string_lossy().into_owned();
-
-            let mut child_route = route.iter().cloned().collect::<Vec<_>>();
-            child_route.push(name.clone());
-
-            match self.read_path(&child_route, &path) {
-                Ok(child_item) => {
-                    children.insert(name, child_item);
-                },
-                Err(_) => {},
-            }
-        }
-
-        Ok(VfsItem::Dir {
-            route: route.iter().cloned().collect::<Vec<_>>(),
-            children,
-        })
+        Ok(full_path)
     }
 
-    fn read_file<P: AsRef<Path>>(&self, route: &[String], path: P) -> Result<VfsItem, ()> {
-        let path = path.as_ref();
-        let mut file = match File::open(path) {
-            Ok(v) => v,
-            Err(_) => return Err(()),
-        };
+    /// Reads the tree rooted at `path`, whose route has already been interned
+    /// as `id` with base name `name`. Recurses by id and name rather than by
+    /// rebuilding an owned route per entry: each child only costs interning
+    /// its own name as a child of `id`, not re-cloning every ancestor name
+    /// the way a fresh `Vec<String>` per entry would. The one exception is the
+    /// ignore check: when any partition has ignore patterns configured,
+    /// `is_ignored` resolves each child's full route.
+    fn read_path(&self, id: FileId, name: &str, path: &Path) -> Result<VfsItem, VfsError> {
+        match self.backend.read_path(path)? {
+            VfsNode::File { contents } => Ok(VfsItem::File {
+                id,
+                name: name.to_string(),
+                contents,
+            }),
+            VfsNode::Dir { child_names } => {
+                let mut children = HashMap::new();
 
-        let mut contents = String::new();
+                for child_name in child_names {
+                    let child_id = self.file_id_child(id, &child_name);
 
-        match file.read_to_string(&mut contents) {
-            Ok(_) => {},
-            Err(_) => return Err(()),
-        }
-
-        Ok(VfsItem::File {
-            route: route.iter().cloned().collect::<Vec<_>>(),
-            contents,
-        })
-    }
+                    if self.is_ignored(child_id) {
+                        continue;
+                    }
 
-    fn read_path<P: AsRef<Path>>(&self, route: &[String], path: P) -> Result<VfsItem, ()> {
-        let path = path.as_ref();
+                    let child_path = path.join(&child_name);
 
-        let metadata = match fs::metadata(path) {
-            Ok(v) => v,
-            Err(_) => return Err(()),
-        };
+                    if let Ok(child_item) = self.read_path(child_id, &child_name, &child_path) {
+                        children.insert(child_name, child_item);
+                    }
+                }
 
-        if metadata.is_dir() {
-            self.read_dir(route, path)
-        } else if metadata.is_file() {
-            self.read_file(route, path)
-        } else {
-            Err(())
+                Ok(VfsItem::Dir {
+                    id,
+                    name: name.to_string(),
+                    children,
+                })
+            },
         }
     }
 
@@ -177,7 +470,7 @@ impl Vfs {
         elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9
     }
 
-    pub fn add_change(&mut self, timestamp: f64, route: Vec<String>) {
+    pub fn add_change(&self, timestamp: f64, route: Vec<String>) {
         if self.config.verbose {
             println!("Received change {:?}, running through plugins...", route);
         }
@@ -188,21 +481,23 @@ impl Vfs {
                     println!("Adding changes from plugin: {:?}", routes);
                 }
 
+                let mut change_history = self.change_history.lock().unwrap();
+
                 for route in routes {
-                    self.change_history.push(VfsChange {
-                        timestamp,
-                        route,
-                    });
+                    let id = self.file_id(&route);
+                    change_history.push(VfsChange { timestamp, id });
                 }
             },
             None => {}
         }
     }
 
-    pub fn changes_since(&self, timestamp: f64) -> &[VfsChange] {
+    pub fn changes_since(&self, timestamp: f64) -> Vec<VfsChange> {
+        let change_history = self.change_history.lock().unwrap();
+
         let mut marker: Option<usize> = None;
 
-        for (index, value) in self.change_history.iter().enumerate().rev() {
+        for (index, value) in change_history.iter().enumerate().rev() {
             if value.timestamp >= timestamp {
                 marker = Some(index);
             } else {
@@ -210,25 +505,652 @@ impl Vfs {
             }
         }
 
-        if let Some(index) = marker {
-            &self.change_history[index..]
+        match marker {
+            Some(index) => change_history[index..].to_vec(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn read(&self, route: &[String]) -> Result<VfsItem, VfsError> {
+        let path = self.route_to_path(route)?;
+        let id = self.file_id(route);
+        let name = route.last().map(String::as_str).unwrap_or_default();
+
+        self.read_path(id, name, &path)
+    }
+
+    /// Writes `item` to disk at `route`, creating any parent directories that
+    /// don't exist yet. Files are written to a sibling temp file and renamed
+    /// over the target, so readers never observe a half-written file.
+    ///
+    /// Records one `VfsChange` per leaf file actually written, not just one
+    /// for `route` itself: writing a `Dir` recurses over its descendants on
+    /// the backend, and `changes_since` consumers key off individual file
+    /// routes (the same kind of route the watcher records), so every nested
+    /// file that changed needs its own entry to stay visible to them.
+    pub fn write(&self, route: &[String], item: VfsItem) -> Result<(), VfsError> {
+        let path = self.route_to_path(route)?;
+
+        self.backend.write(&path, &item)?;
+
+        let timestamp = self.current_time();
+
+        for leaf_route in leaf_file_routes(route, &item) {
+            self.add_change(timestamp, leaf_route);
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the file or directory tree at `route`.
+    ///
+    /// Records one `VfsChange` per leaf file actually removed, not just one
+    /// for `route` itself, mirroring `write`: a real `rm -rf` picked up by
+    /// the watcher yields one change per leaf file via `event_paths`, and a
+    /// `changes_since` consumer watching a specific nested route shouldn't
+    /// see that removal only when it comes from the filesystem and not from
+    /// this method. The tree is read before it's removed from the backend,
+    /// since there's nothing left to read afterward; if that read fails,
+    /// `route` itself is recorded as the sole change, same as `write` does
+    /// for an empty directory.
+    pub fn delete(&self, route: &[String]) -> Result<(), VfsError> {
+        let path = self.route_to_path(route)?;
+
+        let leaf_routes = self.read(route)
+            .map(|item| leaf_file_routes(route, &item))
+            .unwrap_or_else(|_| vec![route.to_vec()]);
+
+        self.backend.delete(&path)?;
+
+        let timestamp = self.current_time();
+
+        for leaf_route in leaf_routes {
+            self.add_change(timestamp, leaf_route);
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether `route` names a file or a directory on the backend,
+    /// without reading its contents.
+    pub fn metadata(&self, route: &[String]) -> Result<VfsMetadata, VfsError> {
+        let path = self.route_to_path(route)?;
+
+        self.backend.metadata(&path)
+    }
+
+    /// Resolves `relative` against the directory containing `anchor`, an
+    /// existing item's route, the way a `require`-style reference between
+    /// two files would be followed. Supports `.` and `..` components; `..`
+    /// that would climb above the anchor's partition root returns `None`
+    /// instead of escaping it.
+    pub fn resolve_relative(&self, anchor: &[String], relative: &str) -> Option<Vec<String>> {
+        let mut route = anchor.to_vec();
+
+        // Start resolution from the directory containing the anchor, not the
+        // anchor itself.
+        route.pop();
+
+        if route.is_empty() {
+            return None;
+        }
+
+        for component in relative.split('/') {
+            match component {
+                "" | "." => {},
+                ".." => {
+                    // The partition name alone is the root; don't pop past it.
+                    if route.len() <= 1 {
+                        return None;
+                    }
+
+                    route.pop();
+                },
+                component => route.push(component.to_string()),
+            }
+        }
+
+        Some(route)
+    }
+}
+
+/// Errors produced by `Vfs` operations that touch disk or resolve routes.
+#[derive(Debug)]
+pub enum VfsError {
+    /// The first component of a route didn't name any partition registered
+    /// with the `Vfs`.
+    NoSuchPartition(String),
+
+    /// The resolved path doesn't exist on disk.
+    NotFound(PathBuf),
+
+    /// Some other IO operation (metadata, read, write, rename, ...) failed.
+    Io(io::Error),
+}
+
+impl fmt::Display for VfsError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VfsError::NoSuchPartition(ref name) => write!(formatter, "no such partition '{}'", name),
+            VfsError::NotFound(ref path) => write!(formatter, "path not found: {}", path.display()),
+            VfsError::Io(ref err) => write!(formatter, "IO error: {}", err),
+        }
+    }
+}
+
+impl error::Error for VfsError {
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            VfsError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for VfsError {
+    fn from(err: io::Error) -> VfsError {
+        VfsError::Io(err)
+    }
+}
+
+/// Maps an `io::Error` from an operation on `path` to a `VfsError`, turning
+/// "not found" into the more specific `VfsError::NotFound`.
+fn io_error_at(err: io::Error, path: &Path) -> VfsError {
+    if err.kind() == io::ErrorKind::NotFound {
+        VfsError::NotFound(path.to_path_buf())
+    } else {
+        VfsError::Io(err)
+    }
+}
+
+fn read_dir_at(path: &Path) -> Result<fs::ReadDir, VfsError> {
+    fs::read_dir(path).map_err(|err| io_error_at(err, path))
+}
+
+/// Writes `item` to `path`, recursing into directories and atomically
+/// rewriting files through a sibling temp file.
+fn write_item(path: &Path, item: &VfsItem) -> Result<(), VfsError> {
+    match *item {
+        VfsItem::File { ref contents, .. } => write_file_atomic(path, contents),
+        VfsItem::Dir { ref children, .. } => {
+            fs::create_dir_all(path)?;
+
+            for (name, child) in children {
+                write_item(&path.join(name), child)?;
+            }
+
+            Ok(())
+        },
+    }
+}
+
+fn write_file_atomic(path: &Path, contents: &str) -> Result<(), VfsError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let temp_path = sibling_temp_path(path);
+    fs::write(&temp_path, contents)?;
+    fs::rename(&temp_path, path)?;
+
+    Ok(())
+}
+
+fn sibling_temp_path(path: &Path) -> PathBuf {
+    let file_name = path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    path.with_file_name(format!(".{}.rojo-tmp", file_name))
+}
+
+/// Whether a path on a `VfsBackend` is a file or a directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VfsMetadata {
+    File,
+    Dir,
+}
+
+/// The raw contents of a single node read from a `VfsBackend`, one level
+/// deep: a directory's children are named but not themselves read yet, so
+/// `Vfs` can apply ignore patterns before recursing into them.
+pub enum VfsNode {
+    File { contents: String },
+    Dir { child_names: Vec<String> },
+}
+
+/// The leaf IO that backs a `Vfs`. `Vfs` itself owns routing, interning,
+/// ignore patterns, and change tracking; a `VfsBackend` only knows how to
+/// read, write, and delete at a concrete `Path`.
+pub trait VfsBackend: Send + Sync {
+    fn read_path(&self, path: &Path) -> Result<VfsNode, VfsError>;
+    fn metadata(&self, path: &Path) -> Result<VfsMetadata, VfsError>;
+    fn write(&self, path: &Path, item: &VfsItem) -> Result<(), VfsError>;
+    fn delete(&self, path: &Path) -> Result<(), VfsError>;
+}
+
+/// The default `VfsBackend`, backed by `std::fs`. This is the behavior `Vfs`
+/// had before backends were extracted.
+pub struct RealFsBackend;
+
+impl VfsBackend for RealFsBackend {
+    fn read_path(&self, path: &Path) -> Result<VfsNode, VfsError> {
+        let metadata = fs::metadata(path).map_err(|err| io_error_at(err, path))?;
+
+        if metadata.is_dir() {
+            let mut child_names = Vec::new();
+
+            for entry in read_dir_at(path)? {
+                let entry = entry.map_err(VfsError::Io)?;
+                child_names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+
+            Ok(VfsNode::Dir { child_names })
+        } else if metadata.is_file() {
+            let mut file = File::open(path).map_err(|err| io_error_at(err, path))?;
+            let mut contents = String::new();
+
+            file.read_to_string(&mut contents).map_err(VfsError::Io)?;
+
+            Ok(VfsNode::File { contents })
+        } else {
+            Err(VfsError::NotFound(path.to_path_buf()))
+        }
+    }
+
+    fn metadata(&self, path: &Path) -> Result<VfsMetadata, VfsError> {
+        let metadata = fs::metadata(path).map_err(|err| io_error_at(err, path))?;
+
+        Ok(if metadata.is_dir() { VfsMetadata::Dir } else { VfsMetadata::File })
+    }
+
+    fn write(&self, path: &Path, item: &VfsItem) -> Result<(), VfsError> {
+        write_item(path, item)
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), VfsError> {
+        let metadata = fs::metadata(path).map_err(|err| io_error_at(err, path))?;
+
+        if metadata.is_dir() {
+            fs::remove_dir_all(path).map_err(VfsError::Io)
         } else {
-            &self.change_history[..0]
+            fs::remove_file(path).map_err(VfsError::Io)
+        }
+    }
+}
+
+enum MemoryEntry {
+    File(String),
+    Dir,
+}
+
+/// An in-process `VfsBackend` backed by a flat map from `Path` to content,
+/// with no filesystem dependency. Meant for tests: seed it with `seed_file`
+/// / `seed_dir`, mount it on a `Vfs` via `Vfs::with_backend`, and assert on
+/// `changes_since` after calling `write`/`delete`/`add_change`.
+pub struct MemoryBackend {
+    entries: Mutex<HashMap<PathBuf, MemoryEntry>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> MemoryBackend {
+        MemoryBackend {
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Seeds a file at `path` with `contents`, creating marker entries for
+    /// any parent directories that don't exist yet.
+    pub fn seed_file<P: Into<PathBuf>>(&self, path: P, contents: String) {
+        let path = path.into();
+        let mut entries = self.entries.lock().unwrap();
+
+        insert_parent_dirs(&mut entries, &path);
+        entries.insert(path, MemoryEntry::File(contents));
+    }
+
+    /// Seeds an empty directory at `path`, creating marker entries for any
+    /// parent directories that don't exist yet.
+    pub fn seed_dir<P: Into<PathBuf>>(&self, path: P) {
+        let path = path.into();
+        let mut entries = self.entries.lock().unwrap();
+
+        insert_parent_dirs(&mut entries, &path);
+        entries.insert(path, MemoryEntry::Dir);
+    }
+}
+
+fn insert_parent_dirs(entries: &mut HashMap<PathBuf, MemoryEntry>, path: &Path) {
+    if let Some(parent) = path.parent() {
+        if parent.parent().is_some() || parent.file_name().is_some() {
+            entries.entry(parent.to_path_buf()).or_insert(MemoryEntry::Dir);
+            insert_parent_dirs(entries, parent);
+        }
+    }
+}
+
+fn write_memory_item(entries: &mut HashMap<PathBuf, MemoryEntry>, path: &Path, item: &VfsItem) {
+    match *item {
+        VfsItem::File { ref contents, .. } => {
+            entries.insert(path.to_path_buf(), MemoryEntry::File(contents.clone()));
+        },
+        VfsItem::Dir { ref children, .. } => {
+            entries.insert(path.to_path_buf(), MemoryEntry::Dir);
+
+            for (name, child) in children {
+                write_memory_item(entries, &path.join(name), child);
+            }
+        },
+    }
+}
+
+impl VfsBackend for MemoryBackend {
+    fn read_path(&self, path: &Path) -> Result<VfsNode, VfsError> {
+        let entries = self.entries.lock().unwrap();
+
+        match entries.get(path) {
+            Some(&MemoryEntry::File(ref contents)) => Ok(VfsNode::File {
+                contents: contents.clone(),
+            }),
+            Some(&MemoryEntry::Dir) => {
+                let mut child_names: Vec<String> = entries.keys()
+                    .filter(|candidate| candidate.parent() == Some(path))
+                    .filter_map(|candidate| candidate.file_name())
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .collect();
+
+                child_names.sort();
+
+                Ok(VfsNode::Dir { child_names })
+            },
+            None => Err(VfsError::NotFound(path.to_path_buf())),
+        }
+    }
+
+    fn metadata(&self, path: &Path) -> Result<VfsMetadata, VfsError> {
+        let entries = self.entries.lock().unwrap();
+
+        match entries.get(path) {
+            Some(&MemoryEntry::File(_)) => Ok(VfsMetadata::File),
+            Some(&MemoryEntry::Dir) => Ok(VfsMetadata::Dir),
+            None => Err(VfsError::NotFound(path.to_path_buf())),
+        }
+    }
+
+    fn write(&self, path: &Path, item: &VfsItem) -> Result<(), VfsError> {
+        let mut entries = self.entries.lock().unwrap();
+
+        insert_parent_dirs(&mut entries, path);
+        write_memory_item(&mut entries, path, item);
+
+        Ok(())
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), VfsError> {
+        let mut entries = self.entries.lock().unwrap();
+
+        if !entries.contains_key(path) {
+            return Err(VfsError::NotFound(path.to_path_buf()));
+        }
+
+        entries.retain(|candidate, _| !candidate.starts_with(path));
+
+        Ok(())
+    }
+}
+
+/// A background thread that watches a fixed set of partitions for filesystem
+/// changes via `notify` and turns them into `VfsChange`s.
+///
+/// Stopping the watcher (either explicitly with `stop` or by dropping it)
+/// joins the background thread, so it's safe to let it go out of scope.
+pub struct VfsWatcher {
+    stop: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl VfsWatcher {
+    fn start(
+        partitions: HashMap<String, PathBuf>,
+        ignores: HashMap<String, IgnoreMatcher>,
+        plugin_chain: &'static PluginChain,
+        start_time: Instant,
+        change_history: Arc<Mutex<Vec<VfsChange>>>,
+        file_interner: Arc<Mutex<PathInterner>>,
+    ) -> VfsWatcher {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+
+        let handle = thread::spawn(move || {
+            let (tx, rx) = mpsc::channel();
+
+            // `notify`'s debounced watcher coalesces bursts of events on the
+            // same path into one, which is exactly the behavior we want.
+            let mut watcher: RecommendedWatcher = match Watcher::new(tx, Duration::from_millis(200)) {
+                Ok(watcher) => watcher,
+                Err(_) => return,
+            };
+
+            for path in partitions.values() {
+                // If a partition doesn't exist on disk (yet), just skip
+                // watching it instead of failing the whole watcher.
+                let _ = watcher.watch(path, RecursiveMode::Recursive);
+            }
+
+            loop {
+                if thread_stop.load(Ordering::SeqCst) {
+                    return;
+                }
+
+                let event = match rx.recv_timeout(Duration::from_millis(200)) {
+                    Ok(event) => event,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                };
+
+                let changed_paths = event_paths(&event);
+
+                if changed_paths.is_empty() {
+                    continue;
+                }
+
+                let elapsed = start_time.elapsed();
+                let timestamp = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+
+                for changed_path in changed_paths {
+                    let route = match path_to_route(&partitions, &changed_path) {
+                        Some(route) => route,
+                        // The event doesn't map cleanly onto any partition; skip it.
+                        None => continue,
+                    };
+
+                    // A change under an ignored directory is skipped too, the
+                    // same way `read` never descends into one.
+                    if (2..=route.len()).any(|len| route_is_ignored(&ignores, &route[..len])) {
+                        continue;
+                    }
+
+                    if let Some(routes) = plugin_chain.handle_file_change(&route) {
+                        let mut change_history = change_history.lock().unwrap();
+                        let mut file_interner = file_interner.lock().unwrap();
+
+                        for route in routes {
+                            let id = file_interner.intern(&route);
+                            change_history.push(VfsChange { timestamp, id });
+                        }
+                    }
+                }
+            }
+        });
+
+        VfsWatcher {
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    /// Signals the watcher's background thread to exit and waits for it to
+    /// finish. Safe to call more than once.
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
         }
     }
+}
+
+impl Drop for VfsWatcher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
 
-    pub fn read(&self, route: &[String]) -> Result<VfsItem, ()> {
-        match self.route_to_path(route) {
-            Some(path) => self.read_path(route, &path),
-            None => Err(()),
+/// Pulls the paths a `notify` event applies to out of the event. Create,
+/// write, chmod and remove events yield their path; every other kind (the
+/// pre-debounce notices, errors, rescans) yields nothing. A rename/move
+/// yields both the source and destination path, since `changes_since` consumers need to see the
+/// source route go away as well as the destination route appear, not just
+/// the latter.
+fn event_paths(event: &DebouncedEvent) -> Vec<PathBuf> {
+    match *event {
+        DebouncedEvent::Create(ref path)
+        | DebouncedEvent::Write(ref path)
+        | DebouncedEvent::Chmod(ref path)
+        | DebouncedEvent::Remove(ref path) => vec![path.clone()],
+        DebouncedEvent::Rename(ref from, ref to) => vec![from.clone(), to.clone()],
+        _ => Vec::new(),
+    }
+}
+
+/// The reverse of `Vfs::route_to_path`: given an absolute path from a
+/// filesystem event, finds the partition whose root is the longest matching
+/// prefix of the path and rebuilds the route relative to it.
+fn path_to_route(partitions: &HashMap<String, PathBuf>, path: &Path) -> Option<Vec<String>> {
+    let mut best: Option<(&str, &PathBuf)> = None;
+
+    for (name, partition_path) in partitions {
+        if !path.starts_with(partition_path) {
+            continue;
         }
+
+        let is_more_specific = match best {
+            Some((_, best_path)) => {
+                partition_path.components().count() > best_path.components().count()
+            },
+            None => true,
+        };
+
+        if is_more_specific {
+            best = Some((name, partition_path));
+        }
+    }
+
+    let (name, partition_path) = best?;
+    let relative = path.strip_prefix(partition_path).ok()?;
+
+    let mut route = vec![name.to_string()];
+    route.extend(relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned()));
+
+    Some(route)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_plugin_chain() -> &'static PluginChain {
+        // No plugins registered, so `handle_file_change` just passes the
+        // changed route through unchanged; leaked to get the `'static`
+        // lifetime `Vfs` expects without a `lazy_static`-style singleton.
+        Box::leak(Box::new(PluginChain::new(Vec::new())))
     }
 
-    pub fn write(&self, _route: &[String], _item: VfsItem) -> Result<(), ()> {
-        unimplemented!()
+    #[test]
+    fn memory_backend_write_and_delete_are_visible_in_changes_since() {
+        let backend = Box::new(MemoryBackend::new());
+        let mut vfs = Vfs::with_backend(Config::default(), test_plugin_chain(), backend);
+
+        vfs.mount_partition("game", PathBuf::from("/game"), &[]);
+
+        let before = vfs.current_time();
+
+        let route = vec!["game".to_string(), "main.lua".to_string()];
+
+        vfs.write(&route, VfsItem::File {
+            id: vfs.file_id(&route),
+            name: "main.lua".to_string(),
+            contents: "print('hi')".to_string(),
+        }).unwrap();
+
+        let after_write = vfs.changes_since(before);
+        assert_eq!(after_write.len(), 1);
+        assert_eq!(vfs.route(after_write[0].id()), route);
+
+        vfs.delete(&route).unwrap();
+
+        let after_delete = vfs.changes_since(before);
+        assert_eq!(after_delete.len(), 2);
+        assert_eq!(vfs.route(after_delete[1].id()), route);
     }
 
-    pub fn delete(&self, _route: &[String]) -> Result<(), ()> {
-        unimplemented!()
+    #[test]
+    fn deleting_a_directory_records_one_change_per_leaf_file() {
+        let backend = Box::new(MemoryBackend::new());
+        let mut vfs = Vfs::with_backend(Config::default(), test_plugin_chain(), backend);
+
+        vfs.mount_partition("game", PathBuf::from("/game"), &[]);
+
+        let dir_route = vec!["game".to_string(), "src".to_string()];
+        let a_route = vec!["game".to_string(), "src".to_string(), "a.lua".to_string()];
+        let b_route = vec!["game".to_string(), "src".to_string(), "b.lua".to_string()];
+
+        let mut children = HashMap::new();
+        children.insert("a.lua".to_string(), VfsItem::File {
+            id: vfs.file_id(&a_route),
+            name: "a.lua".to_string(),
+            contents: "return 1".to_string(),
+        });
+        children.insert("b.lua".to_string(), VfsItem::File {
+            id: vfs.file_id(&b_route),
+            name: "b.lua".to_string(),
+            contents: "return 2".to_string(),
+        });
+
+        vfs.write(&dir_route, VfsItem::Dir {
+            id: vfs.file_id(&dir_route),
+            name: "src".to_string(),
+            children,
+        }).unwrap();
+
+        let before_delete = vfs.current_time();
+
+        vfs.delete(&dir_route).unwrap();
+
+        let after_delete = vfs.changes_since(before_delete);
+        let mut deleted_routes: Vec<Vec<String>> = after_delete.iter()
+            .map(|change| vfs.route(change.id()))
+            .collect();
+        deleted_routes.sort();
+
+        let mut expected = vec![a_route, b_route];
+        expected.sort();
+
+        assert_eq!(deleted_routes, expected);
+    }
+
+    #[test]
+    fn rooted_ignore_pattern_anchors_to_the_partition_root() {
+        let mut ignores = HashMap::new();
+        ignores.insert("game".to_string(), IgnoreMatcher::compile(&["/build".to_string()]));
+
+        assert!(route_is_ignored(&ignores, &["game".to_string(), "build".to_string()]));
+        assert!(!route_is_ignored(&ignores, &[
+            "game".to_string(),
+            "src".to_string(),
+            "build".to_string(),
+        ]));
     }
 }